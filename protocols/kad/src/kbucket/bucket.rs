@@ -24,10 +24,23 @@
 //! > checked in this module. This is an invariant that must hold across all
 //! > buckets in a `KBucketsTable` and hence is enforced by the public API
 //! > of the `KBucketsTable` and in particular the public `Entry` API.
+//!
+//! > **Note**: `KBucket::new`'s `capacity` parameter and the `InsertResult`
+//! > enum's `TVal` parameter are breaking changes to this module's public
+//! > API. The sibling `kbucket/mod.rs` (`KBucketsTable`) and `kbucket/entry.rs`
+//! > modules, which construct buckets and match on `InsertResult`, need a
+//! > corresponding update, but are not present in this source tree to update
+//! > alongside it.
 
 use super::*;
+use smallvec::SmallVec;
+use std::convert::TryFrom;
 
-/// Maximum number of nodes in a bucket, i.e. the (fixed) `k` parameter.
+/// The default number of nodes in a bucket, i.e. the default `k` parameter.
+///
+/// This value is used as the inline capacity of the `SmallVec` that backs
+/// each bucket; buckets may be created with a different (runtime) capacity
+/// via [`KBucket::new`].
 pub const MAX_NODES_PER_BUCKET: usize = 20;
 
 /// A `PendingNode` is a `Node` that is pending insertion into a `KBucket`.
@@ -39,28 +52,88 @@ pub struct PendingNode<TPeerId, TVal> {
     /// The status of the pending node.
     status: NodeStatus,
 
-    /// The instant at which the pending node is eligible for insertion into a bucket.
+    /// The key of the node that the pending node is scheduled to replace,
+    /// recorded at the time the pending node was created. Looked up by key
+    /// rather than assumed to remain at a fixed position, since the
+    /// candidate's position in the bucket may change (e.g. if it is demoted
+    /// to [`NodeStatus::Unreachable`] by [`KBucket::on_failure`]) or it may
+    /// be evicted outright in the meantime by a fast eviction path (e.g. the
+    /// banned-node or failure-threshold paths in [`KBucket::insert`]).
+    candidate: Key<TPeerId>,
+
+    /// The instant at which the current backoff window of the pending node
+    /// elapses, making it eligible for the next probe, or for replacing the
+    /// least-protected node if `attempt` has reached the end of the bucket's
+    /// backoff schedule.
     replace: Instant,
+
+    /// The number of times the candidate for replacement has been probed
+    /// without a status update, i.e. the index into the bucket's backoff
+    /// schedule of the window that is currently running.
+    attempt: usize,
 }
 
 /// The status of a node in a bucket.
 ///
 /// The status of a node in a bucket together with the time of the
 /// last status change determines the position of the node in a
-/// bucket.
+/// bucket, from least to most protected against eviction:
+/// `Unreachable` and `Unstable` nodes sort before `Disconnected`
+/// nodes, which in turn sort before `Connected` nodes.
 #[derive(PartialEq, Eq, Debug, Copy, Clone)]
 pub enum NodeStatus {
     /// The node is considered connected.
     Connected,
     /// The node is considered disconnected.
-    Disconnected
+    Disconnected,
+    /// The node has repeatedly failed to be dialed and is considered
+    /// unreachable. It is one of the first candidates for replacement
+    /// when a pending node is applied.
+    Unreachable,
+    /// The node's connection has flapped (i.e. repeatedly connected and
+    /// disconnected) and it is considered unstable. Like `Unreachable`
+    /// nodes, it is one of the first candidates for replacement.
+    Unstable,
+}
+
+/// The relative protection of a [`NodeStatus`] against eviction: higher
+/// values sort towards the tail of a bucket's nodes and are evicted last.
+fn rank(status: NodeStatus) -> u8 {
+    match status {
+        NodeStatus::Unreachable => 0,
+        NodeStatus::Unstable => 1,
+        NodeStatus::Disconnected => 2,
+        NodeStatus::Connected => 3,
+    }
+}
+
+/// Performs a partial, in-place Fisher-Yates shuffle of `indices`, returning
+/// up to `n` of them in random order without shuffling (or cloning) the
+/// remainder.
+fn partial_shuffle<R: rand::Rng>(indices: &mut [usize], n: usize, rng: &mut R) -> Vec<usize> {
+    let n = n.min(indices.len());
+    for i in 0 .. n {
+        let j = rng.gen_range(i, indices.len());
+        indices.swap(i, j);
+    }
+    indices[.. n].to_vec()
 }
 
 impl<TPeerId, TVal> PendingNode<TPeerId, TVal> {
+    fn new(node: Node<TPeerId, TVal>, status: NodeStatus, candidate: Key<TPeerId>, replace: Instant) -> Self {
+        PendingNode { node, status, candidate, replace, attempt: 0 }
+    }
+
     pub fn key(&self) -> &Key<TPeerId> {
         &self.node.key
     }
 
+    /// Returns the key of the node that this pending node is scheduled to
+    /// replace, as recorded when the pending node was created.
+    pub fn candidate(&self) -> &Key<TPeerId> {
+        &self.candidate
+    }
+
     pub fn status(&self) -> NodeStatus {
         self.status
     }
@@ -69,6 +142,12 @@ impl<TPeerId, TVal> PendingNode<TPeerId, TVal> {
         &mut self.node.value
     }
 
+    /// Returns the number of times the node has been probed and found
+    /// unresponsive since becoming the candidate for replacement.
+    pub fn attempt(&self) -> usize {
+        self.attempt
+    }
+
     pub fn is_ready(&self) -> bool {
         Instant::now() >= self.replace
     }
@@ -76,6 +155,22 @@ impl<TPeerId, TVal> PendingNode<TPeerId, TVal> {
     pub fn set_ready_at(&mut self, t: Instant) {
         self.replace = t;
     }
+
+    /// Advances to the next window of the given backoff schedule, if the
+    /// schedule has a step beyond the current attempt. Returns `true` and
+    /// resets `is_ready` to reflect the new window if so, meaning the
+    /// candidate should be re-probed; returns `false` without changing
+    /// anything if the schedule is exhausted, meaning the candidate should
+    /// now be replaced.
+    fn advance(&mut self, backoff: &[Duration]) -> bool {
+        if self.attempt + 1 < backoff.len() {
+            self.attempt += 1;
+            self.replace = Instant::now() + backoff[self.attempt];
+            true
+        } else {
+            false
+        }
+    }
 }
 
 /// A `Node` in a bucket, representing a peer participating
@@ -89,57 +184,125 @@ pub struct Node<TPeerId, TVal> {
     pub value: TVal,
 }
 
+/// The default number of consecutive failures of a connected node at or
+/// above which it becomes a candidate for eviction in favour of a pending node.
+const DEFAULT_FAILURE_THRESHOLD: u32 = 3;
+
+/// The default number of consecutive dial failures of a disconnected node
+/// at or above which it is demoted to [`NodeStatus::Unreachable`].
+const DEFAULT_UNREACHABLE_THRESHOLD: u32 = 3;
+
+/// The default number of connect/disconnect flaps at or above which a node is
+/// demoted to [`NodeStatus::Unstable`] instead of [`NodeStatus::Disconnected`].
+const DEFAULT_UNSTABLE_THRESHOLD: u32 = 3;
+
+/// The default reputation threshold below which a node is considered
+/// banned and an immediate candidate for eviction, regardless of status.
+const DEFAULT_BANNED_THRESHOLD: i32 = i32::MIN / 100 * 82;
+
+/// A `Node` together with the bookkeeping a `KBucket` uses to make eviction
+/// and ordering decisions.
+#[derive(Debug, Clone)]
+struct Entry<TPeerId, TVal> {
+    node: Node<TPeerId, TVal>,
+    /// The current status of the node, determining its position in the
+    /// bucket relative to other nodes.
+    status: NodeStatus,
+    /// The number of consecutive failures recorded for this node since its
+    /// last success. Reset to `0` by [`KBucket::on_success`].
+    failures: u32,
+    /// The number of times this node has gone from `Connected` to
+    /// `Disconnected`, i.e. how often its connection has flapped.
+    flaps: u32,
+    /// The instant at which the node was inserted or last confirmed to be
+    /// reachable via [`KBucket::on_success`].
+    last_seen: Instant,
+    /// The reputation of the node, adjusted via [`KBucket::add_reputation`]
+    /// and decaying towards `0` over time via [`KBucket::tick`]. A node
+    /// whose reputation falls below the bucket's `banned_threshold` is
+    /// considered banned.
+    reputation: i32,
+}
+
+impl<TPeerId, TVal> Entry<TPeerId, TVal> {
+    fn new(node: Node<TPeerId, TVal>, status: NodeStatus) -> Self {
+        Entry { node, status, failures: 0, flaps: 0, last_seen: Instant::now(), reputation: 0 }
+    }
+}
+
 /// The position of a node in a `KBucket`, i.e. a non-negative integer
-/// in the range `[0, MAX_NODES_PER_BUCKET)`.
+/// in the range `[0, capacity)`.
 #[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
 pub struct Position(usize);
 
-/// A `KBucket` is a list of up to `MAX_NODES_PER_BUCKET` `Key`s and associated values,
-/// ordered from least-recently connected to most-recently connected.
+/// A `KBucket` is a list of up to `capacity` `Key`s and associated values,
+/// ordered from least-protected (i.e. first to be evicted) to
+/// most-protected, with `Connected` nodes always at the tail.
 #[derive(Debug, Clone)]
 pub struct KBucket<TPeerId, TVal> {
     /// The nodes contained in the bucket.
-    nodes: ArrayVec<[Node<TPeerId, TVal>; MAX_NODES_PER_BUCKET]>,
+    nodes: SmallVec<[Entry<TPeerId, TVal>; MAX_NODES_PER_BUCKET]>,
 
-    /// The position (index) in `nodes` that marks the first connected node.
-    ///
-    /// Since the entries in `nodes` are ordered from least-recently connected to
-    /// most-recently connected, all entries above this index are also considered
-    /// connected, i.e. the range `[0, first_connected_pos)` marks the sub-list of entries
-    /// that are considered disconnected and the range
-    /// `[first_connected_pos, MAX_NODES_PER_BUCKET)` marks sub-list of entries that are
-    /// considered connected.
-    ///
-    /// `None` indicates that there are no connected entries in the bucket, i.e.
-    /// the bucket is either empty, or contains only entries for peers that are
-    /// considered disconnected.
-    first_connected_pos: Option<usize>,
-
-    /// A node that is pending to be inserted into a full bucket, should the
-    /// least-recently connected (and currently disconnected) node not be
-    /// marked as connected within `unresponsive_timeout`.
+    /// The maximum number of nodes that may be stored in the bucket, i.e.
+    /// the `k` parameter. Defaults to [`MAX_NODES_PER_BUCKET`] but can be
+    /// configured per bucket via [`KBucket::new`].
+    capacity: usize,
+
+    /// A node that is pending to be inserted into a full bucket, should its
+    /// candidate for replacement (the least-protected node when the pending
+    /// node was created) not be updated to [`NodeStatus::Connected`] before
+    /// `backoff` runs its course.
     pending: Option<PendingNode<TPeerId, TVal>>,
 
-    /// The timeout window before a new pending node is eligible for insertion,
-    /// if the least-recently connected node is not updated as being connected
-    /// in the meantime.
-    pending_timeout: Duration
+    /// The number of consecutive failures at or above which a connected node
+    /// becomes a candidate for eviction when a new connected node is
+    /// inserted into a bucket that is full of connected nodes.
+    failure_threshold: u32,
+
+    /// The number of consecutive dial failures at or above which a disconnected
+    /// node is demoted to [`NodeStatus::Unreachable`].
+    unreachable_threshold: u32,
+
+    /// The number of connect/disconnect flaps at or above which a node is demoted
+    /// to [`NodeStatus::Unstable`] instead of [`NodeStatus::Disconnected`].
+    unstable_threshold: u32,
+
+    /// The reputation threshold below which a node is considered banned and
+    /// an immediate candidate for eviction, regardless of status.
+    banned_threshold: i32,
+
+    /// The instant at which reputations were last decayed via [`KBucket::tick`].
+    last_tick: Instant,
+
+    /// The schedule of probe windows for the pending node: each element is
+    /// the duration of the backoff window for the corresponding [`PendingNode::attempt`].
+    /// The node is evicted in favour of the pending node once the window
+    /// for the last entry elapses without a status update. Defaults to a
+    /// single window of `pending_timeout`.
+    backoff: Vec<Duration>,
 }
 
 /// The result of inserting an entry into a bucket.
 #[must_use]
 #[derive(Debug, Clone, PartialEq, Eq)]
-pub enum InsertResult<TPeerId> {
+pub enum InsertResult<TPeerId, TVal> {
      /// The entry has been successfully inserted.
-     Inserted,
+     Inserted {
+         /// The node that was evicted to make room for the inserted node, if
+         /// insertion replaced a banned node outright (see
+         /// [`KBucket::add_reputation`]) or a connected node whose failure
+         /// count reached the failure threshold (see [`KBucket::on_failure`]),
+         /// rather than going through the pending mechanism.
+         evicted: Option<Node<TPeerId, TVal>>
+     },
      /// The entry is pending insertion because the relevant bucket is currently full.
-     /// The entry is inserted after a timeout elapsed, if the status of the
-     /// least-recently connected (and currently disconnected) node in the bucket
-     /// is not updated before the timeout expires.
+     /// The entry is inserted after the bucket's backoff schedule has run its
+     /// course, if the least-protected entry in the bucket is not updated to
+     /// [`NodeStatus::Connected`] before then.
      Pending {
-         /// The key of the least-recently connected entry that is currently considered
-         /// disconnected and whose corresponding peer should be checked for connectivity
-         /// in order to prevent it from being evicted. If connectivity to the peer is
+         /// The key of the least-protected entry in the bucket, whose
+         /// corresponding peer should be checked for connectivity in order to
+         /// prevent it from being evicted. If connectivity to the peer is
          /// re-established, the corresponding entry should be updated with
          /// [`NodeStatus::Connected`].
          disconnected: Key<TPeerId>
@@ -163,16 +326,164 @@ impl<TPeerId, TVal> KBucket<TPeerId, TVal>
 where
     TPeerId: Clone
 {
-    /// Creates a new `KBucket` with the given timeout for pending entries.
-    pub fn new(pending_timeout: Duration) -> Self {
+    /// Creates a new `KBucket` with the given timeout for pending entries
+    /// and the given capacity, i.e. the maximum number of nodes the bucket
+    /// may hold at once (the `k` parameter).
+    pub fn new(pending_timeout: Duration, capacity: usize) -> Self {
         KBucket {
-            nodes: ArrayVec::new(),
-            first_connected_pos: None,
+            nodes: SmallVec::new(),
+            capacity,
             pending: None,
-            pending_timeout,
+            failure_threshold: DEFAULT_FAILURE_THRESHOLD,
+            unreachable_threshold: DEFAULT_UNREACHABLE_THRESHOLD,
+            unstable_threshold: DEFAULT_UNSTABLE_THRESHOLD,
+            banned_threshold: DEFAULT_BANNED_THRESHOLD,
+            last_tick: Instant::now(),
+            backoff: vec![pending_timeout],
         }
     }
 
+    /// Returns the maximum number of nodes the bucket may hold at once.
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Sets the backoff schedule for re-probing the candidate for
+    /// replacement while a node is pending, and returns the bucket for
+    /// chaining. Each element is the duration of a probe window; the
+    /// pending node is applied, evicting the candidate, once the window
+    /// for the last entry elapses without a status update. Must not be
+    /// empty.
+    pub fn set_backoff(&mut self, schedule: Vec<Duration>) -> &mut Self {
+        assert!(!schedule.is_empty());
+        self.backoff = schedule;
+        self
+    }
+
+    /// Returns the key of the node that is the current candidate for
+    /// replacement by the pending node, if the probe window for its current
+    /// attempt has elapsed. The behaviour layer should use this to drive a
+    /// re-ping of the candidate.
+    ///
+    /// This is the candidate recorded on the `PendingNode` itself when it was
+    /// created, not necessarily the node currently at [`Position`] `0`: the
+    /// candidate's position can change without discarding the pending node
+    /// (e.g. `on_failure` may demote a *different* disconnected node to
+    /// `Unreachable`, which re-sorts it to position `0`), and the recorded
+    /// candidate can even be evicted by another path entirely (e.g. the
+    /// banned-node or failure-threshold fast paths in `insert_entry`); see
+    /// `apply_pending`, which re-resolves the candidate by key for this
+    /// reason rather than assuming it is still at position `0`.
+    pub fn probe_target(&self) -> Option<&Key<TPeerId>> {
+        self.pending.as_ref()
+            .filter(|p| p.is_ready())
+            .map(|p| p.candidate())
+    }
+
+    /// Sets the number of consecutive failures at or above which a connected node
+    /// becomes a candidate for eviction, and returns the bucket for chaining.
+    pub fn set_failure_threshold(&mut self, threshold: u32) -> &mut Self {
+        self.failure_threshold = threshold;
+        self
+    }
+
+    /// Sets the number of consecutive dial failures at or above which a
+    /// disconnected node is demoted to [`NodeStatus::Unreachable`], and
+    /// returns the bucket for chaining.
+    pub fn set_unreachable_threshold(&mut self, threshold: u32) -> &mut Self {
+        self.unreachable_threshold = threshold;
+        self
+    }
+
+    /// Sets the number of connect/disconnect flaps at or above which a node is
+    /// demoted to [`NodeStatus::Unstable`], and returns the bucket for
+    /// chaining.
+    pub fn set_unstable_threshold(&mut self, threshold: u32) -> &mut Self {
+        self.unstable_threshold = threshold;
+        self
+    }
+
+    /// Sets the reputation threshold below which a node is considered
+    /// banned, and returns the bucket for chaining.
+    pub fn set_banned_threshold(&mut self, threshold: i32) -> &mut Self {
+        self.banned_threshold = threshold;
+        self
+    }
+
+    /// Adjusts the reputation of the node with the given key by `delta`
+    /// (saturating), if it is in the bucket.
+    pub fn add_reputation(&mut self, key: &Key<TPeerId>, delta: i32) {
+        if let Some(pos) = self.position(key) {
+            self.nodes[pos.0].reputation = self.nodes[pos.0].reputation.saturating_add(delta);
+        }
+    }
+
+    /// Returns the reputation of the node with the given key, if it is in
+    /// the bucket.
+    pub fn reputation(&self, key: &Key<TPeerId>) -> Option<i32> {
+        self.position(key).map(|p| self.nodes[p.0].reputation)
+    }
+
+    /// Decays the reputation of every node in the bucket linearly towards
+    /// `0`, by one point per second elapsed since the previous tick.
+    pub fn tick(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last_tick).as_secs();
+        self.last_tick = now;
+        if elapsed == 0 {
+            return
+        }
+        let decay = i32::try_from(elapsed).unwrap_or(i32::MAX);
+        for entry in &mut self.nodes {
+            entry.reputation = if entry.reputation > 0 {
+                entry.reputation.saturating_sub(decay).max(0)
+            } else {
+                entry.reputation.saturating_add(decay).min(0)
+            };
+        }
+    }
+
+    /// Records a failure (e.g. an unanswered ping) for the node with the
+    /// given key, if it is in the bucket. Once a disconnected node has
+    /// accumulated enough consecutive failures, it is demoted to
+    /// [`NodeStatus::Unreachable`].
+    pub fn on_failure(&mut self, key: &Key<TPeerId>) {
+        if let Some(pos) = self.position(key) {
+            self.nodes[pos.0].failures = self.nodes[pos.0].failures.saturating_add(1);
+            if self.nodes[pos.0].status == NodeStatus::Disconnected
+                && self.nodes[pos.0].failures >= self.unreachable_threshold
+            {
+                let mut entry = self.nodes.remove(pos.0);
+                entry.status = NodeStatus::Unreachable;
+                match self.insert_entry(entry) {
+                    InsertResult::Inserted { .. } => {},
+                    _ => unreachable!("The node is removed before being (re)inserted.")
+                }
+            }
+        }
+    }
+
+    /// Records a success for the node with the given key, resetting its
+    /// failure count and refreshing its last-seen time, if it is in the
+    /// bucket.
+    pub fn on_success(&mut self, key: &Key<TPeerId>) {
+        if let Some(pos) = self.position(key) {
+            self.nodes[pos.0].failures = 0;
+            self.nodes[pos.0].last_seen = Instant::now();
+        }
+    }
+
+    /// Returns the number of consecutive failures recorded for the node with
+    /// the given key, if it is in the bucket.
+    pub fn failures(&self, key: &Key<TPeerId>) -> Option<u32> {
+        self.position(key).map(|p| self.nodes[p.0].failures)
+    }
+
+    /// Returns the instant at which the node with the given key was last
+    /// confirmed to be reachable, if it is in the bucket.
+    pub fn last_seen(&self, key: &Key<TPeerId>) -> Option<Instant> {
+        self.position(key).map(|p| self.nodes[p.0].last_seen)
+    }
+
     /// Returns a reference to the pending node of the bucket, if there is any.
     pub fn pending(&self) -> Option<&PendingNode<TPeerId, TVal>> {
         self.pending.as_ref()
@@ -191,63 +502,103 @@ where
 
     /// Returns a reference to a node in the bucket.
     pub fn get(&self, key: &Key<TPeerId>) -> Option<&Node<TPeerId, TVal>> {
-        self.position(key).map(|p| &self.nodes[p.0])
+        self.position(key).map(|p| &self.nodes[p.0].node)
     }
 
     /// Returns an iterator over the nodes in the bucket, together with their status.
     pub fn iter(&self) -> impl Iterator<Item = (&Node<TPeerId, TVal>, NodeStatus)> {
-        self.nodes.iter().enumerate().map(move |(p, n)| (n, self.status(Position(p))))
+        self.nodes.iter().map(|e| (&e.node, e.status))
     }
 
-    /// Inserts the pending node into the bucket, if its timeout has elapsed,
-    /// replacing the least-recently connected node.
+    /// Returns a reference to a uniformly random node in the bucket, or
+    /// `None` if the bucket is empty.
+    pub fn random<R: rand::Rng>(&self, rng: &mut R) -> Option<&Node<TPeerId, TVal>> {
+        if self.nodes.is_empty() {
+            return None
+        }
+        let index = rng.gen_range(0, self.nodes.len());
+        Some(&self.nodes[index].node)
+    }
+
+    /// Returns up to `n` distinct nodes from the bucket, chosen at random
+    /// and biased towards `Connected` nodes: as many distinct connected
+    /// nodes as possible are sampled first, before falling back to the
+    /// remaining (non-connected) nodes to make up the requested number.
+    ///
+    /// Does not allocate a clone of the bucket; only a scratch list of
+    /// indices into it is shuffled, via a partial Fisher-Yates shuffle.
+    pub fn sample<R: rand::Rng>(&self, n: usize, rng: &mut R) -> Vec<&Node<TPeerId, TVal>> {
+        let mut connected = Vec::new();
+        let mut others = Vec::new();
+        for (pos, entry) in self.nodes.iter().enumerate() {
+            if entry.status == NodeStatus::Connected {
+                connected.push(pos);
+            } else {
+                others.push(pos);
+            }
+        }
+
+        let mut sampled = partial_shuffle(&mut connected, n, rng);
+        if sampled.len() < n {
+            sampled.extend(partial_shuffle(&mut others, n - sampled.len(), rng));
+        }
+
+        sampled.into_iter().map(|p| &self.nodes[p].node).collect()
+    }
+
+    /// Inserts the pending node into the bucket, once the last window of the
+    /// backoff schedule has elapsed, replacing its recorded candidate for
+    /// replacement. When an earlier window elapses, the pending node only
+    /// advances to the next window and `None` is returned.
     ///
     /// If a pending node has been inserted, its key is returned together with
-    /// the node that was replaced. `None` indicates that the nodes in the
-    /// bucket remained unchanged.
+    /// the node that was replaced, if the candidate was still present (it may
+    /// have been evicted in the meantime by another path, e.g. the
+    /// banned-node or failure-threshold fast paths in `insert_entry`).
+    /// `None` indicates that the nodes in the bucket remained unchanged.
     pub fn apply_pending(&mut self) -> Option<AppliedPending<TPeerId, TVal>> {
-        if let Some(pending) = self.pending.take() {
+        if let Some(mut pending) = self.pending.take() {
             if pending.replace <= Instant::now() {
-                if self.nodes.is_full() {
-                    if self.status(Position(0)) == NodeStatus::Connected {
-                        // The bucket is full with connected nodes. Drop the pending node.
-                        return None
-                    }
-                    // The pending node will be inserted.
-                    let inserted = pending.node.key.clone();
-                    // A connected pending node goes at the end of the list for
-                    // the connected peers, removing the least-recently connected.
-                    if pending.status == NodeStatus::Connected {
-                        let evicted = Some(self.nodes.remove(0));
-                        self.first_connected_pos = self.first_connected_pos
-                            .map_or_else(
-                                | | Some(self.nodes.len()),
-                                |p| p.checked_sub(1));
-                        self.nodes.push(pending.node);
+                if pending.advance(&self.backoff) {
+                    // The probe window for the current attempt has elapsed,
+                    // but the backoff schedule has further steps: advance to
+                    // the next window instead of evicting outright, giving
+                    // the candidate another chance to respond to a re-probe.
+                    self.pending = Some(pending);
+                    return None
+                }
+                let inserted = pending.node.key.clone();
+                // Re-resolve the candidate by key rather than assuming it is
+                // still at position 0: its position may have changed (e.g.
+                // demoted to `Unreachable` by `on_failure`), or it may have
+                // been evicted outright by a fast eviction path (e.g. the
+                // banned-node or failure-threshold paths in `insert_entry`)
+                // while this pending node was waiting out its backoff.
+                match self.position(&pending.candidate) {
+                    Some(pos) if self.nodes.len() >= self.capacity => {
+                        // The candidate is still in the bucket and the
+                        // bucket is still full: evict it to make room for
+                        // the pending node, which is placed according to
+                        // its status.
+                        let evicted = Some(self.nodes.remove(pos.0).node);
+                        let entry = Entry::new(pending.node, pending.status);
+                        let insert_pos = self.nodes.iter()
+                            .position(|e| rank(e.status) > rank(entry.status))
+                            .unwrap_or_else(|| self.nodes.len());
+                        self.nodes.insert(insert_pos, entry);
                         return Some(AppliedPending { inserted, evicted })
                     }
-                    // A disconnected pending node goes at the end of the list
-                    // for the disconnected peers.
-                    else if let Some(p) = self.first_connected_pos {
-                        if let Some(insert_pos) = p.checked_sub(1) {
-                            let evicted = Some(self.nodes.remove(0));
-                            self.nodes.insert(insert_pos, pending.node);
-                            return Some(AppliedPending { inserted, evicted })
+                    _ => {
+                        // Either the candidate is gone (already evicted by
+                        // another path) or there is now room without
+                        // evicting anyone; either way, fall back to a fresh
+                        // insertion attempt, which handles both cases
+                        // correctly on its own.
+                        return match self.insert(pending.node, pending.status) {
+                            InsertResult::Inserted { evicted } =>
+                                Some(AppliedPending { inserted, evicted }),
+                            InsertResult::Pending { .. } | InsertResult::Full => None
                         }
-                    } else {
-                        // All nodes are disconnected. Insert the new node as the most
-                        // recently disconnected, removing the least-recently disconnected.
-                        let evicted = Some(self.nodes.remove(0));
-                        self.nodes.push(pending.node);
-                        return Some(AppliedPending { inserted, evicted })
-                    }
-                } else {
-                    // There is room in the bucket, so just insert the pending node.
-                    let inserted = pending.node.key.clone();
-                    match self.insert(pending.node, pending.status) {
-                        InsertResult::Inserted =>
-                            return Some(AppliedPending { inserted, evicted: None }),
-                        _ => unreachable!("Bucket is not full.")
                     }
                 }
             } else {
@@ -255,7 +606,7 @@ where
             }
         }
 
-        return None
+        None
     }
 
     /// Updates the status of the pending node, if any.
@@ -267,14 +618,31 @@ where
 
     /// Updates the status of the node referred to by the given key, if it is
     /// in the bucket.
+    ///
+    /// A node whose connection flaps, i.e. that goes from `Connected` to
+    /// `Disconnected` sufficiently often, is demoted to `Unstable` instead
+    /// of `Disconnected`.
     pub fn update(&mut self, key: &Key<TPeerId>, status: NodeStatus) {
         if let Some(pos) = self.position(key) {
-            let node = self.nodes.remove(pos.0);
-            if pos == Position(0) && status == NodeStatus::Connected {
+            let mut entry = self.nodes.remove(pos.0);
+            if status == NodeStatus::Connected
+                && self.pending.as_ref().map_or(false, |p| p.candidate() == key)
+            {
+                // The pending node's candidate for replacement has itself
+                // become connected: there is nothing left to replace it
+                // with, so discard the pending node.
                 self.pending = None
             }
-            match self.insert(node, status) {
-                InsertResult::Inserted => {},
+            if status == NodeStatus::Disconnected && entry.status == NodeStatus::Connected {
+                entry.flaps = entry.flaps.saturating_add(1);
+            }
+            entry.status = if status == NodeStatus::Disconnected && entry.flaps >= self.unstable_threshold {
+                NodeStatus::Unstable
+            } else {
+                status
+            };
+            match self.insert_entry(entry) {
+                InsertResult::Inserted { .. } => {},
                 _ => unreachable!("The node is removed before being (re)inserted.")
             }
         }
@@ -286,61 +654,106 @@ where
     ///
     ///   * `NodeStatus::Connected`: If the bucket is full and either all nodes are connected
     ///     or there is already a pending node, insertion fails with `InsertResult::Full`.
-    ///     If the bucket is full but at least one node is disconnected and there is no pending
-    ///     node, the new node is inserted as pending, yielding `InsertResult::Pending`.
+    ///     If the bucket is full but at least one node is less protected and there is no
+    ///     pending node, the new node is inserted as pending, yielding `InsertResult::Pending`.
     ///     Otherwise the bucket has free slots and the new node is added to the end of the
     ///     bucket as the most-recently connected node.
+    ///     Before any of the above applies to a full bucket, a banned node is
+    ///     replaced directly, and in a bucket full of connected nodes the node
+    ///     with the most failures at or above the failure threshold is evicted;
+    ///     either yields `InsertResult::Inserted` with the evicted node.
     ///
-    ///   * `NodeStatus::Disconnected`: If the bucket is full, insertion fails with
+    ///   * Any other status: If the bucket is full, insertion fails with
     ///     `InsertResult::Full`. Otherwise the bucket has free slots and the new node
-    ///     is inserted at the position preceding the first connected node,
-    ///     i.e. as the most-recently disconnected node. If there are no connected nodes,
-    ///     the new node is added as the last element of the bucket.
+    ///     is inserted just before the first node of a more protected status,
+    ///     i.e. as the most-recently updated node of its status.
     ///
-    pub fn insert(&mut self, node: Node<TPeerId, TVal>, status: NodeStatus) -> InsertResult<TPeerId> {
-        match status {
-            NodeStatus::Connected => {
-                if self.nodes.is_full() {
-                    if self.first_connected_pos == Some(0) || self.pending.is_some() {
-                        return InsertResult::Full
-                    } else {
-                        self.pending = Some(PendingNode {
-                            node,
-                            status: NodeStatus::Connected,
-                            replace: Instant::now() + self.pending_timeout,
-                        });
-                        return InsertResult::Pending {
-                            disconnected: self.nodes[0].key.clone()
+    pub fn insert(&mut self, node: Node<TPeerId, TVal>, status: NodeStatus) -> InsertResult<TPeerId, TVal> {
+        self.insert_entry(Entry::new(node, status))
+    }
+
+    /// Returns the position of the banned node (i.e. one whose reputation has
+    /// fallen below `banned_threshold`) with the lowest reputation, if any.
+    fn lowest_reputation_banned(&self) -> Option<usize> {
+        self.nodes.iter()
+            .enumerate()
+            .filter(|(_, e)| e.reputation < self.banned_threshold)
+            .min_by_key(|(_, e)| e.reputation)
+            .map(|(p, _)| p)
+    }
+
+    /// Inserts an already-tracked entry into the bucket, according to its
+    /// status, preserving its failure count, flap count and last-seen time.
+    fn insert_entry(&mut self, entry: Entry<TPeerId, TVal>) -> InsertResult<TPeerId, TVal> {
+        if entry.status == NodeStatus::Connected {
+            if self.nodes.len() >= self.capacity {
+                // A banned node, regardless of its status, is always the
+                // preferred eviction candidate: replace the one with the
+                // lowest reputation directly, bypassing the pending
+                // mechanism. This may evict the node a pending insertion is
+                // currently scheduled to replace; that is fine, since
+                // `apply_pending` re-resolves its candidate by key rather
+                // than assuming it is still present, and falls back to a
+                // fresh insertion attempt if it is gone.
+                if let Some(pos) = self.lowest_reputation_banned() {
+                    let evicted = Some(self.nodes.remove(pos).node);
+                    self.nodes.push(entry);
+                    return InsertResult::Inserted { evicted }
+                }
+
+                if self.status(Position(0)) == NodeStatus::Connected {
+                    // The bucket is full with connected nodes. Evict the
+                    // connected node with the highest failure count at or
+                    // above the configured threshold directly, if any, rather
+                    // than giving up.
+                    //
+                    // `apply_pending` reaches this path too: when its
+                    // candidate has been evicted in the meantime (e.g. by the
+                    // banned-node path above), it falls back to `insert`.
+                    let evict_pos = self.nodes.iter()
+                        .enumerate()
+                        .filter(|(_, e)| e.failures >= self.failure_threshold)
+                        .max_by_key(|(_, e)| e.failures)
+                        .map(|(p, _)| p);
+                    return match evict_pos {
+                        Some(pos) => {
+                            let evicted = Some(self.nodes.remove(pos).node);
+                            self.nodes.push(entry);
+                            InsertResult::Inserted { evicted }
                         }
+                        None => InsertResult::Full
                     }
-                }
-                let pos = self.nodes.len();
-                self.first_connected_pos = self.first_connected_pos.or(Some(pos));
-                self.nodes.push(node);
-                InsertResult::Inserted
-            }
-            NodeStatus::Disconnected => {
-                if self.nodes.is_full() {
+                } else if self.pending.is_some() {
+                    return InsertResult::Full
+                } else if entry.reputation < self.banned_threshold {
+                    // A banned node is never inserted as pending.
                     return InsertResult::Full
-                }
-                if let Some(ref mut first_connected_pos) = self.first_connected_pos {
-                    self.nodes.insert(*first_connected_pos, node);
-                    *first_connected_pos += 1;
                 } else {
-                    self.nodes.push(node);
+                    let disconnected = self.nodes[0].node.key.clone();
+                    let replace = Instant::now() + self.backoff[0];
+                    self.pending = Some(PendingNode::new(
+                        entry.node, NodeStatus::Connected, disconnected.clone(), replace,
+                    ));
+                    return InsertResult::Pending { disconnected }
                 }
-                InsertResult::Inserted
             }
+            self.nodes.push(entry);
+            return InsertResult::Inserted { evicted: None }
+        }
+
+        if self.nodes.len() >= self.capacity {
+            return InsertResult::Full
         }
+        let insert_pos = self.nodes.iter()
+            .position(|e| rank(e.status) > rank(entry.status))
+            .unwrap_or_else(|| self.nodes.len());
+        self.nodes.insert(insert_pos, entry);
+        InsertResult::Inserted { evicted: None }
     }
 
     /// Returns the status of the node at the given position.
     pub fn status(&self, pos: Position) -> NodeStatus {
-        if self.first_connected_pos.map_or(false, |i| pos.0 >= i) {
-            NodeStatus::Connected
-        } else {
-            NodeStatus::Disconnected
-        }
+        self.nodes[pos.0].status
     }
 
     /// Checks whether the given position refers to a connected node.
@@ -355,17 +768,27 @@ where
 
     /// Gets the number of entries in the bucket that are considered connected.
     pub fn num_connected(&self) -> usize {
-        self.first_connected_pos.map_or(0, |i| self.nodes.len() - i)
+        self.nodes.iter().filter(|e| e.status == NodeStatus::Connected).count()
     }
 
     /// Gets the number of entries in the bucket that are considered disconnected.
     pub fn num_disconnected(&self) -> usize {
-        self.nodes.len() - self.num_connected()
+        self.nodes.iter().filter(|e| e.status == NodeStatus::Disconnected).count()
+    }
+
+    /// Gets the number of entries in the bucket that are considered unreachable.
+    pub fn num_unreachable(&self) -> usize {
+        self.nodes.iter().filter(|e| e.status == NodeStatus::Unreachable).count()
+    }
+
+    /// Gets the number of entries in the bucket that are considered unstable.
+    pub fn num_unstable(&self) -> usize {
+        self.nodes.iter().filter(|e| e.status == NodeStatus::Unstable).count()
     }
 
     /// Gets the position of an node in the bucket.
     pub fn position(&self, key: &Key<TPeerId>) -> Option<Position> {
-        self.nodes.iter().position(|p| &p.key == key).map(Position)
+        self.nodes.iter().position(|p| &p.node.key == key).map(Position)
     }
 
     /// Gets a mutable reference to the node identified by the given key.
@@ -373,7 +796,7 @@ where
     /// Returns `None` if the given key does not refer to an node in the
     /// bucket.
     pub fn get_mut(&mut self, key: &Key<TPeerId>) -> Option<&mut Node<TPeerId, TVal>> {
-        self.nodes.iter_mut().find(move |p| &p.key == key)
+        self.nodes.iter_mut().find(move |p| &p.node.key == key).map(|e| &mut e.node)
     }
 }
 
@@ -395,11 +818,16 @@ mod tests {
         }
     }
 
+    /// Capacities exercised by the parameterized bucket tests, covering the
+    /// default `k` as well as smaller and larger configurations.
+    const TEST_CAPACITIES: [usize; 4] = [1, 8, MAX_NODES_PER_BUCKET, MAX_NODES_PER_BUCKET * 2];
+
     fn fill_bucket(bucket: &mut KBucket<PeerId, ()>, status: NodeStatus) {
-        for i in 0 .. MAX_NODES_PER_BUCKET - bucket.num_entries() {
+        let num_to_fill = bucket.capacity() - bucket.num_entries();
+        for i in 0 .. num_to_fill {
             let key = Key::new(PeerId::random());
             let node = Node { key, value: () };
-            assert_eq!(InsertResult::Inserted, bucket.insert(node, status));
+            assert_eq!(InsertResult::Inserted { evicted: None }, bucket.insert(node, status));
             assert_eq!(bucket.num_entries(), i + 1);
         }
         assert!(bucket.pending().is_none());
@@ -407,8 +835,8 @@ mod tests {
 
     #[test]
     fn ordering() {
-        fn prop(status: Vec<NodeStatus>) -> bool {
-            let mut bucket = KBucket::<PeerId, ()>::new(Duration::from_secs(1));
+        fn prop(status: Vec<NodeStatus>, capacity: usize) -> bool {
+            let mut bucket = KBucket::<PeerId, ()>::new(Duration::from_secs(1), capacity);
 
             // The expected lists of connected and disconnected nodes.
             let mut connected = VecDeque::new();
@@ -418,12 +846,12 @@ mod tests {
             for status in status {
                 let key = Key::new(PeerId::random());
                 let node = Node { key: key.clone(), value: () };
-                let full = bucket.num_entries() == MAX_NODES_PER_BUCKET;
+                let full = bucket.num_entries() == capacity;
                 match bucket.insert(node, status) {
-                    InsertResult::Inserted => {
+                    InsertResult::Inserted { .. } => {
                         let vec = match status {
                             NodeStatus::Connected => &mut connected,
-                            NodeStatus::Disconnected => &mut disconnected
+                            _ => &mut disconnected
                         };
                         if full {
                             vec.pop_front();
@@ -441,7 +869,6 @@ mod tests {
 
             // Split the list of nodes at the first connected node.
             let first_connected_pos = nodes.iter().position(|(s,_)| *s == NodeStatus::Connected);
-            assert_eq!(bucket.first_connected_pos, first_connected_pos);
             let tail = first_connected_pos.map_or(Vec::new(), |p| nodes.split_off(p));
 
             // All nodes before the first connected node must be disconnected and
@@ -452,76 +879,82 @@ mod tests {
             tail == Vec::from(connected)
         }
 
-        quickcheck(prop as fn(_) -> _);
+        fn prop_all_capacities(status: Vec<NodeStatus>) -> bool {
+            TEST_CAPACITIES.iter().all(|&capacity| prop(status.clone(), capacity))
+        }
+
+        quickcheck(prop_all_capacities as fn(_) -> _);
     }
 
     #[test]
     fn full_bucket() {
-        let mut bucket = KBucket::<PeerId, ()>::new(Duration::from_secs(1));
-
-        // Fill the bucket with disconnected nodes.
-        fill_bucket(&mut bucket, NodeStatus::Disconnected);
-
-        // Trying to insert another disconnected node fails.
-        let key = Key::new(PeerId::random());
-        let node = Node { key, value: () };
-        match bucket.insert(node, NodeStatus::Disconnected) {
-            InsertResult::Full => {},
-            x => panic!("{:?}", x)
-        }
+        for &capacity in &TEST_CAPACITIES {
+            let mut bucket = KBucket::<PeerId, ()>::new(Duration::from_secs(1), capacity);
 
-        // One-by-one fill the bucket with connected nodes, replacing the disconnected ones.
-        for i in 0 .. MAX_NODES_PER_BUCKET {
-            let (first, first_status) = bucket.iter().next().unwrap();
-            let first_disconnected = first.clone();
-            assert_eq!(first_status, NodeStatus::Disconnected);
+            // Fill the bucket with disconnected nodes.
+            fill_bucket(&mut bucket, NodeStatus::Disconnected);
 
-            // Add a connected node, which is expected to be pending, scheduled to
-            // replace the first (i.e. least-recently connected) node.
+            // Trying to insert another disconnected node fails.
             let key = Key::new(PeerId::random());
-            let node = Node { key: key.clone(), value: () };
-            match bucket.insert(node.clone(), NodeStatus::Connected) {
-                InsertResult::Pending { disconnected } =>
-                    assert_eq!(disconnected, first_disconnected.key),
-                x => panic!("{:?}", x)
-            }
-
-            // Trying to insert another connected node fails.
-            match bucket.insert(node.clone(), NodeStatus::Connected) {
+            let node = Node { key, value: () };
+            match bucket.insert(node, NodeStatus::Disconnected) {
                 InsertResult::Full => {},
                 x => panic!("{:?}", x)
             }
 
-            assert!(bucket.pending().is_some());
+            // One-by-one fill the bucket with connected nodes, replacing the disconnected ones.
+            for i in 0 .. capacity {
+                let (first, first_status) = bucket.iter().next().unwrap();
+                let first_disconnected = first.clone();
+                assert_eq!(first_status, NodeStatus::Disconnected);
 
-            // Apply the pending node.
-            let pending = bucket.pending_mut().expect("No pending node.");
-            pending.set_ready_at(Instant::now() - Duration::from_secs(1));
-            let result = bucket.apply_pending();
-            assert_eq!(result, Some(AppliedPending {
-                inserted: key.clone(),
-                evicted: Some(first_disconnected)
-            }));
-            assert_eq!(Some((&node, NodeStatus::Connected)), bucket.iter().last());
-            assert!(bucket.pending().is_none());
-            assert_eq!(Some(MAX_NODES_PER_BUCKET - (i + 1)), bucket.first_connected_pos);
-        }
+                // Add a connected node, which is expected to be pending, scheduled to
+                // replace the first (i.e. least-recently connected) node.
+                let key = Key::new(PeerId::random());
+                let node = Node { key: key.clone(), value: () };
+                match bucket.insert(node.clone(), NodeStatus::Connected) {
+                    InsertResult::Pending { disconnected } =>
+                        assert_eq!(disconnected, first_disconnected.key),
+                    x => panic!("{:?}", x)
+                }
 
-        assert!(bucket.pending().is_none());
-        assert_eq!(MAX_NODES_PER_BUCKET, bucket.num_entries());
+                // Trying to insert another connected node fails.
+                match bucket.insert(node.clone(), NodeStatus::Connected) {
+                    InsertResult::Full => {},
+                    x => panic!("{:?}", x)
+                }
 
-        // Trying to insert another connected node fails.
-        let key = Key::new(PeerId::random());
-        let node = Node { key, value: () };
-        match bucket.insert(node, NodeStatus::Connected) {
-            InsertResult::Full => {},
-            x => panic!("{:?}", x)
+                assert!(bucket.pending().is_some());
+
+                // Apply the pending node.
+                let pending = bucket.pending_mut().expect("No pending node.");
+                pending.set_ready_at(Instant::now() - Duration::from_secs(1));
+                let result = bucket.apply_pending();
+                assert_eq!(result, Some(AppliedPending {
+                    inserted: key.clone(),
+                    evicted: Some(first_disconnected)
+                }));
+                assert_eq!(Some((&node, NodeStatus::Connected)), bucket.iter().last());
+                assert!(bucket.pending().is_none());
+                assert_eq!(i + 1, bucket.num_connected());
+            }
+
+            assert!(bucket.pending().is_none());
+            assert_eq!(capacity, bucket.num_entries());
+
+            // Trying to insert another connected node fails.
+            let key = Key::new(PeerId::random());
+            let node = Node { key, value: () };
+            match bucket.insert(node, NodeStatus::Connected) {
+                InsertResult::Full => {},
+                x => panic!("{:?}", x)
+            }
         }
     }
 
     #[test]
     fn full_bucket_discard_pending() {
-        let mut bucket = KBucket::<PeerId, ()>::new(Duration::from_secs(1));
+        let mut bucket = KBucket::<PeerId, ()>::new(Duration::from_secs(1), MAX_NODES_PER_BUCKET);
         fill_bucket(&mut bucket, NodeStatus::Disconnected);
         let (first, _) = bucket.iter().next().unwrap();
         let first_disconnected = first.clone();
@@ -545,8 +978,198 @@ mod tests {
 
         // The initially disconnected node is now the most-recently connected.
         assert_eq!(Some((&first_disconnected, NodeStatus::Connected)), bucket.iter().last());
-        assert_eq!(bucket.position(&first_disconnected.key).map(|p| p.0), bucket.first_connected_pos);
         assert_eq!(1, bucket.num_connected());
         assert_eq!(MAX_NODES_PER_BUCKET - 1, bucket.num_disconnected());
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn full_bucket_evicts_failed_connected_node() {
+        let mut bucket = KBucket::<PeerId, ()>::new(Duration::from_secs(1), MAX_NODES_PER_BUCKET);
+        fill_bucket(&mut bucket, NodeStatus::Connected);
+
+        // Record enough failures against the least-recently connected node
+        // to reach the default failure threshold.
+        let (failing, _) = bucket.iter().next().unwrap();
+        let failing_key = failing.key.clone();
+        for _ in 0 .. 3 {
+            bucket.on_failure(&failing_key);
+        }
+
+        // Insert a new connected node. Even though all nodes in the bucket
+        // are connected, the failed node is evicted directly in its favour,
+        // without going through the pending mechanism.
+        let key = Key::new(PeerId::random());
+        let node = Node { key: key.clone(), value: () };
+        match bucket.insert(node.clone(), NodeStatus::Connected) {
+            InsertResult::Inserted { evicted } =>
+                assert_eq!(evicted.map(|n| n.key), Some(failing_key.clone())),
+            x => panic!("{:?}", x)
+        }
+        assert!(bucket.pending().is_none());
+        assert!(bucket.get(&failing_key).is_none());
+        assert_eq!(Some((&node, NodeStatus::Connected)), bucket.iter().last());
+        assert_eq!(MAX_NODES_PER_BUCKET, bucket.num_entries());
+        assert_eq!(MAX_NODES_PER_BUCKET, bucket.num_connected());
+    }
+
+    #[test]
+    fn pending_node_is_reprobed_through_backoff_schedule_before_eviction() {
+        let mut bucket = KBucket::<PeerId, ()>::new(Duration::from_secs(1), MAX_NODES_PER_BUCKET);
+        bucket.set_backoff(vec![
+            Duration::from_secs(1),
+            Duration::from_secs(1),
+            Duration::from_secs(1),
+        ]);
+        fill_bucket(&mut bucket, NodeStatus::Disconnected);
+        let (first, _) = bucket.iter().next().unwrap();
+        let first_disconnected = first.clone();
+
+        let key = Key::new(PeerId::random());
+        let node = Node { key: key.clone(), value: () };
+        bucket.insert(node.clone(), NodeStatus::Connected);
+
+        // The first two elapsed probe windows only advance the backoff
+        // schedule; the candidate is re-probed, not evicted.
+        for attempt in 0 .. 2 {
+            assert_eq!(bucket.pending().map(|p| p.attempt()), Some(attempt));
+            let pending = bucket.pending_mut().expect("No pending node.");
+            pending.set_ready_at(Instant::now() - Duration::from_secs(1));
+            assert_eq!(bucket.probe_target(), Some(&first_disconnected.key));
+            assert!(bucket.apply_pending().is_none());
+            assert!(bucket.get(&first_disconnected.key).is_some());
+        }
+
+        // The final backoff window elapsing without a status update evicts
+        // the candidate in favour of the pending node.
+        assert_eq!(bucket.pending().map(|p| p.attempt()), Some(2));
+        let pending = bucket.pending_mut().expect("No pending node.");
+        pending.set_ready_at(Instant::now() - Duration::from_secs(1));
+        let result = bucket.apply_pending();
+        assert_eq!(result, Some(AppliedPending {
+            inserted: key,
+            evicted: Some(first_disconnected)
+        }));
+        assert!(bucket.pending().is_none());
+    }
+
+    #[test]
+    fn full_bucket_replaces_banned_node_directly() {
+        let mut bucket = KBucket::<PeerId, ()>::new(Duration::from_secs(1), MAX_NODES_PER_BUCKET);
+        bucket.set_banned_threshold(-10);
+        fill_bucket(&mut bucket, NodeStatus::Connected);
+
+        // Ban the least-recently connected node.
+        let (banned, _) = bucket.iter().next().unwrap();
+        let banned_key = banned.key.clone();
+        bucket.add_reputation(&banned_key, -11);
+        assert!(bucket.reputation(&banned_key).unwrap() < -10);
+
+        // Inserting a new connected node directly replaces the banned node,
+        // without going through the pending mechanism.
+        let key = Key::new(PeerId::random());
+        let node = Node { key: key.clone(), value: () };
+        match bucket.insert(node.clone(), NodeStatus::Connected) {
+            InsertResult::Inserted { evicted } =>
+                assert_eq!(evicted.map(|n| n.key), Some(banned_key.clone())),
+            x => panic!("{:?}", x)
+        }
+        assert!(bucket.pending().is_none());
+        assert!(bucket.get(&banned_key).is_none());
+        assert_eq!(Some((&node, NodeStatus::Connected)), bucket.iter().last());
+        assert_eq!(MAX_NODES_PER_BUCKET, bucket.num_entries());
+    }
+
+    #[test]
+    fn reputation_decays_towards_zero_on_tick() {
+        let mut bucket = KBucket::<PeerId, ()>::new(Duration::from_secs(1), MAX_NODES_PER_BUCKET);
+        let key = Key::new(PeerId::random());
+        bucket.insert(Node { key: key.clone(), value: () }, NodeStatus::Connected);
+        bucket.add_reputation(&key, -100);
+        assert_eq!(bucket.reputation(&key), Some(-100));
+
+        let now = Instant::now() + Duration::from_secs(40);
+        bucket.tick(now);
+        assert_eq!(bucket.reputation(&key), Some(-60));
+
+        bucket.tick(now + Duration::from_secs(100));
+        assert_eq!(bucket.reputation(&key), Some(0));
+    }
+
+    #[test]
+    fn random_and_sample_prefer_connected_nodes() {
+        let mut bucket = KBucket::<PeerId, ()>::new(Duration::from_secs(1), MAX_NODES_PER_BUCKET);
+        let mut rng = rand::thread_rng();
+
+        assert!(bucket.random(&mut rng).is_none());
+        assert!(bucket.sample(3, &mut rng).is_empty());
+
+        let connected = Key::new(PeerId::random());
+        bucket.insert(Node { key: connected.clone(), value: () }, NodeStatus::Connected);
+        let disconnected = Key::new(PeerId::random());
+        bucket.insert(Node { key: disconnected, value: () }, NodeStatus::Disconnected);
+
+        assert!(bucket.random(&mut rng).is_some());
+
+        // Sampling a single node is biased towards the connected one.
+        let sample = bucket.sample(1, &mut rng);
+        assert_eq!(sample.len(), 1);
+        assert_eq!(sample[0].key, connected);
+
+        // Sampling more nodes than are connected falls back to the rest,
+        // without ever producing duplicates.
+        let sample = bucket.sample(2, &mut rng);
+        assert_eq!(sample.len(), 2);
+        let keys = sample.iter().map(|n| n.key.clone()).collect::<Vec<_>>();
+        let all = bucket.iter().map(|(n, _)| n.key.clone()).collect::<Vec<_>>();
+        assert!(all.iter().all(|k| keys.contains(k)));
+
+        // Requesting more than is available just returns what there is.
+        assert_eq!(bucket.sample(10, &mut rng).len(), 2);
+    }
+
+    #[test]
+    fn unreachable_and_unstable_are_evicted_first() {
+        let mut bucket = KBucket::<PeerId, ()>::new(Duration::from_secs(1), 4);
+
+        let connected = Key::new(PeerId::random());
+        bucket.insert(Node { key: connected.clone(), value: () }, NodeStatus::Connected);
+
+        let disconnected = Key::new(PeerId::random());
+        bucket.insert(Node { key: disconnected.clone(), value: () }, NodeStatus::Disconnected);
+
+        let unreachable = Key::new(PeerId::random());
+        bucket.insert(Node { key: unreachable.clone(), value: () }, NodeStatus::Unreachable);
+
+        let unstable = Key::new(PeerId::random());
+        bucket.insert(Node { key: unstable.clone(), value: () }, NodeStatus::Unstable);
+
+        assert_eq!(1, bucket.num_connected());
+        assert_eq!(1, bucket.num_disconnected());
+        assert_eq!(1, bucket.num_unreachable());
+        assert_eq!(1, bucket.num_unstable());
+
+        // The least-protected node (position 0) is neither the connected nor
+        // the merely disconnected node.
+        let least_protected = bucket.iter().next().map(|(n, _)| n.key.clone());
+        assert!(least_protected == Some(unreachable) || least_protected == Some(unstable));
+
+        // The connected node remains the most-protected, at the tail.
+        assert_eq!(Some((&Node { key: connected, value: () }, NodeStatus::Connected)), bucket.iter().last());
+    }
+
+    #[test]
+    fn disconnect_promotes_to_unstable_after_repeated_flaps() {
+        let mut bucket = KBucket::<PeerId, ()>::new(Duration::from_secs(1), MAX_NODES_PER_BUCKET);
+        let key = Key::new(PeerId::random());
+        bucket.insert(Node { key: key.clone(), value: () }, NodeStatus::Connected);
+
+        // Flap between connected and disconnected repeatedly.
+        for _ in 0 .. 3 {
+            bucket.update(&key, NodeStatus::Disconnected);
+            bucket.update(&key, NodeStatus::Connected);
+        }
+        bucket.update(&key, NodeStatus::Disconnected);
+
+        assert_eq!(Some(NodeStatus::Unstable), bucket.position(&key).map(|p| bucket.status(p)));
+    }
+}